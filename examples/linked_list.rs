@@ -0,0 +1,103 @@
+//! A small intrusive, circular, doubly linked list.
+//!
+//! Every node is simultaneously a potential list head and a potential list
+//! element: an empty list is just a node whose `next`/`prev` point back to
+//! itself, and "inserting" a node links it in between two existing ones.
+//! This is the same shape as the kernel's `list_head` and is what lets the
+//! synchronization primitives in this directory queue up parked threads
+//! without any separate allocation.
+
+use core::{cell::Cell, marker::PhantomPinned, pin::Pin, ptr};
+
+use pinned_init::*;
+
+#[pin_data(PinnedDrop)]
+pub struct ListHead {
+    next: Cell<*mut ListHead>,
+    prev: Cell<*mut ListHead>,
+    #[pin]
+    _pin: PhantomPinned,
+}
+
+impl ListHead {
+    /// Creates a new, empty list node that links only to itself.
+    #[inline]
+    pub fn new() -> impl PinInit<Self> {
+        unsafe {
+            init::pin_init_from_closure(|slot: *mut Self| {
+                (*slot).next = Cell::new(slot);
+                (*slot).prev = Cell::new(slot);
+                Ok(())
+            })
+        }
+    }
+
+    /// Creates a new node already linked in immediately before `list`, i.e.
+    /// at the tail of the list that `list` is the head of.
+    #[inline]
+    pub fn insert_prev(list: &ListHead) -> impl PinInit<Self> + '_ {
+        unsafe {
+            init::pin_init_from_closure(move |slot: *mut Self| {
+                let list_ptr = list as *const ListHead as *mut ListHead;
+                let prev = list.prev.get();
+                (*slot).next = Cell::new(list_ptr);
+                (*slot).prev = Cell::new(prev);
+                (*prev).next.set(slot);
+                (*list_ptr).prev.set(slot);
+                Ok(())
+            })
+        }
+    }
+
+    /// Removes `self` from whatever list it is a member of, restoring it to
+    /// a standalone, self-linked node.
+    #[inline]
+    pub fn unlink(&self) {
+        let next = self.next.get();
+        let prev = self.prev.get();
+        unsafe {
+            (*prev).next.set(next);
+            (*next).prev.set(prev);
+        }
+        self.next.set(self as *const ListHead as *mut ListHead);
+        self.prev.set(self as *const ListHead as *mut ListHead);
+    }
+
+    /// Returns whether this node is not linked to any other node.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        ptr::eq(self.next.get(), self)
+    }
+
+    /// Returns the first element after this node (treating `self` as the
+    /// list head), or `None` if the list is empty.
+    #[inline]
+    pub fn next(&self) -> Option<ptr::NonNull<ListHead>> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(unsafe { ptr::NonNull::new_unchecked(self.next.get()) })
+        }
+    }
+
+    /// Counts the number of elements in the list (excluding `self`).
+    #[inline]
+    #[allow(dead_code)]
+    pub fn size(&self) -> usize {
+        let mut count = 0;
+        let mut cur = self.next.get();
+        while !ptr::eq(cur, self) {
+            count += 1;
+            cur = unsafe { (*cur).next.get() };
+        }
+        count
+    }
+}
+
+#[pinned_drop]
+impl PinnedDrop for ListHead {
+    #[inline]
+    fn drop(self: Pin<&mut Self>) {
+        self.unlink();
+    }
+}
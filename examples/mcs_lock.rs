@@ -0,0 +1,181 @@
+#![feature(allocator_api)]
+//! An MCS queue lock: unlike [`SpinLock`](mutex.rs), each waiting thread
+//! spins on a field of its own stack-pinned node instead of a single shared
+//! `AtomicBool`, so contention doesn't bounce one cache line between every
+//! core. See `mutex.rs` for the simpler test-and-set lock this improves on.
+use core::{
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+};
+use std::{
+    sync::Arc,
+    thread::{self, sleep, Builder},
+    time::Duration,
+};
+
+use pinned_init::*;
+
+/// A single thread's queue node. Once passed to [`McsLock::lock`], its
+/// address is published into the lock's `tail` (and possibly a
+/// predecessor's `next`), so it must stay put for as long as the returned
+/// guard is alive — callers stack-pin one per `lock()` call and let the
+/// guard borrow it, rather than `lock()` pinning one on its own stack frame
+/// (which would be freed while the lock is still held).
+#[pin_data]
+pub struct Node {
+    next: AtomicPtr<Node>,
+    locked: AtomicBool,
+}
+
+impl Node {
+    #[inline]
+    pub fn new() -> impl PinInit<Self> {
+        pin_init!(Self {
+            next: AtomicPtr::new(ptr::null_mut()),
+            locked: AtomicBool::new(false),
+        })
+    }
+}
+
+#[pin_data]
+pub struct McsLock<T> {
+    tail: AtomicPtr<Node>,
+    data: core::cell::UnsafeCell<T>,
+}
+
+impl<T> McsLock<T> {
+    #[inline]
+    pub fn new(val: T) -> impl PinInit<Self> {
+        pin_init!(Self {
+            tail: AtomicPtr::new(ptr::null_mut()),
+            data: core::cell::UnsafeCell::new(val),
+        })
+    }
+
+    /// Acquires the lock using `node` as this thread's queue node. `node`
+    /// must be pinned for at least as long as the returned guard is alive
+    /// (typically via `stack_pin_init!` right before the call), since its
+    /// address may be published to other threads while the lock is held.
+    #[inline]
+    pub fn lock<'a>(&'a self, node: Pin<&'a mut Node>) -> McsLockGuard<'a, T> {
+        let node: &mut Node = Pin::into_inner(node);
+        let node_ptr: *mut Node = node;
+
+        let pred = self.tail.swap(node_ptr, Ordering::AcqRel);
+        if !pred.is_null() {
+            node.locked.store(true, Ordering::Relaxed);
+            unsafe { (*pred).next.store(node_ptr, Ordering::Release) };
+            while node.locked.load(Ordering::Acquire) {
+                core::hint::spin_loop();
+            }
+        }
+        McsLockGuard {
+            lock: self,
+            node_ptr,
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for McsLock<T> {}
+unsafe impl<T: Send> Sync for McsLock<T> {}
+
+pub struct McsLockGuard<'a, T> {
+    lock: &'a McsLock<T>,
+    node_ptr: *mut Node,
+}
+
+impl<'a, T> Drop for McsLockGuard<'a, T> {
+    #[inline]
+    fn drop(&mut self) {
+        let node = unsafe { &*self.node_ptr };
+        if node.next.load(Ordering::Acquire).is_null() {
+            if self
+                .lock
+                .tail
+                .compare_exchange(
+                    self.node_ptr,
+                    ptr::null_mut(),
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                // No one joined the queue behind us; nothing left to hand off to.
+                return;
+            }
+            // A successor is mid-enqueue: its `tail.swap` has landed but its
+            // `pred.next.store` hasn't published yet. Spin for it.
+            while node.next.load(Ordering::Acquire).is_null() {
+                core::hint::spin_loop();
+            }
+        }
+        let successor = node.next.load(Ordering::Acquire);
+        unsafe { (*successor).locked.store(false, Ordering::Release) };
+    }
+}
+
+impl<'a, T> Deref for McsLockGuard<'a, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for McsLockGuard<'a, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+/// Stack-pins a fresh [`Node`] in the caller's scope and locks `$mtx` with
+/// it, binding the guard to `$guard`. Each call needs its own node, since its
+/// address is published for the duration of that particular critical section.
+macro_rules! mcs_lock {
+    (let $guard:ident = $mtx:expr) => {
+        stack_pin_init!(let node = Node::new());
+        let node = match node {
+            Ok(n) => n,
+            Err(e) => match e {},
+        };
+        let mut $guard = $mtx.lock(node);
+    };
+}
+
+fn main() {
+    let mtx: Pin<Arc<McsLock<usize>>> = Arc::pin_init(McsLock::new(0)).unwrap();
+    let mut handles = vec![];
+    let thread_count = 20;
+    let workload = 1_000_000;
+    for i in 0..thread_count {
+        let mtx = mtx.clone();
+        handles.push(
+            Builder::new()
+                .name(format!("worker #{i}"))
+                .spawn(move || {
+                    for _ in 0..workload {
+                        mcs_lock!(let guard = mtx);
+                        *guard += 1;
+                    }
+                    println!("{i} halfway");
+                    sleep(Duration::from_millis((i as u64) * 10));
+                    for _ in 0..workload {
+                        mcs_lock!(let guard = mtx);
+                        *guard += 1;
+                    }
+                    println!("{i} finished");
+                })
+                .expect("should not fail"),
+        );
+    }
+    for h in handles {
+        h.join().expect("thread paniced");
+    }
+    mcs_lock!(let guard = mtx);
+    println!("{:?}", &*guard);
+    assert_eq!(*guard, workload * thread_count * 2);
+}
@@ -1,14 +1,16 @@
 #![feature(allocator_api)]
 use core::{
     cell::{Cell, UnsafeCell},
+    marker::PhantomData,
     ops::{Deref, DerefMut},
     pin::Pin,
+    ptr,
     sync::atomic::{AtomicBool, Ordering},
 };
 use std::{
     sync::Arc,
     thread::{self, park, sleep, Builder, Thread},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use pinned_init::*;
@@ -17,101 +19,311 @@ use pinned_init::*;
 pub mod linked_list;
 use linked_list::*;
 
-pub struct SpinLock {
+/// A strategy for what to do on each iteration of a busy-wait loop, as in
+/// the `spin` crate. `Spin` preserves the original pure-spinning behavior;
+/// `Yield` and `ExponentialBackoff` trade latency for being a better
+/// neighbor to other threads on the same core.
+pub trait RelaxStrategy: Default {
+    fn relax(&self);
+}
+
+/// Spins the CPU with no other side effects. The default, and equivalent to
+/// the hardcoded behavior this type replaces.
+#[derive(Default)]
+pub struct Spin;
+
+impl RelaxStrategy for Spin {
+    #[inline]
+    fn relax(&self) {
+        core::hint::spin_loop();
+    }
+}
+
+/// Yields the current OS thread's timeslice on every iteration.
+#[derive(Default)]
+pub struct Yield;
+
+impl RelaxStrategy for Yield {
+    #[inline]
+    fn relax(&self) {
+        thread::yield_now();
+    }
+}
+
+/// Spins for a doubling number of iterations, up to a cap, before falling
+/// back to yielding the thread.
+pub struct ExponentialBackoff {
+    spins: Cell<u32>,
+}
+
+impl Default for ExponentialBackoff {
+    #[inline]
+    fn default() -> Self {
+        Self { spins: Cell::new(1) }
+    }
+}
+
+impl RelaxStrategy for ExponentialBackoff {
+    #[inline]
+    fn relax(&self) {
+        const CAP: u32 = 1 << 10;
+        let spins = self.spins.get();
+        if spins >= CAP {
+            thread::yield_now();
+            return;
+        }
+        for _ in 0..spins {
+            core::hint::spin_loop();
+        }
+        self.spins.set(spins * 2);
+    }
+}
+
+pub struct SpinLock<R = Spin> {
     inner: AtomicBool,
+    _relax: PhantomData<R>,
 }
 
-impl SpinLock {
+impl<R: RelaxStrategy> SpinLock<R> {
     #[inline]
-    pub fn acquire(&self) -> SpinLockGuard<'_> {
+    pub fn acquire(&self) -> SpinLockGuard<'_, R> {
+        let relax = R::default();
         while self
             .inner
             .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
             .is_err()
-        {}
+        {
+            relax.relax();
+        }
         SpinLockGuard(self)
     }
 
+    /// Creates a lock that relaxes using `R` instead of the default `Spin`.
     #[inline]
-    pub const fn new() -> Self {
+    pub const fn new_with() -> Self {
         Self {
             inner: AtomicBool::new(false),
+            _relax: PhantomData,
         }
     }
 }
 
-pub struct SpinLockGuard<'a>(&'a SpinLock);
+impl SpinLock<Spin> {
+    #[inline]
+    pub const fn new() -> Self {
+        Self::new_with()
+    }
+}
+
+pub struct SpinLockGuard<'a, R = Spin>(&'a SpinLock<R>);
 
-impl Drop for SpinLockGuard<'_> {
+impl<R> Drop for SpinLockGuard<'_, R> {
     #[inline]
     fn drop(&mut self) {
         self.0.inner.store(false, Ordering::Release);
     }
 }
 
+/// The result of a locking operation that can observe poisoning: an
+/// `Err(PoisonError)` still carries the guard, for callers that want to
+/// recover the possibly-inconsistent data anyway.
+pub type LockResult<Guard> = Result<Guard, PoisonError<Guard>>;
+
+/// Returned from a lock method when the mutex was poisoned by a panic while
+/// a guard was held. Mirrors `std::sync::PoisonError`.
+pub struct PoisonError<Guard> {
+    guard: Guard,
+}
+
+impl<Guard> PoisonError<Guard> {
+    #[inline]
+    fn new(guard: Guard) -> Self {
+        Self { guard }
+    }
+
+    /// Consumes this error, returning the guard that was being held anyway.
+    #[inline]
+    pub fn into_inner(self) -> Guard {
+        self.guard
+    }
+}
+
+// Implemented by hand, rather than derived, since the guard itself has no
+// `Debug`/`Display` impl and isn't printed; only a fixed message is, as in
+// `std::sync::PoisonError`.
+impl<Guard> core::fmt::Debug for PoisonError<Guard> {
+    #[inline]
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("PoisonError { .. }")
+    }
+}
+
+impl<Guard> core::fmt::Display for PoisonError<Guard> {
+    #[inline]
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        "poisoned lock: another task failed inside".fmt(f)
+    }
+}
+
+impl<Guard> std::error::Error for PoisonError<Guard> {}
+
 #[pin_data]
-pub struct CMutex<T> {
+pub struct CMutex<T, R = Spin> {
     #[pin]
     wait_list: ListHead,
-    spin_lock: SpinLock,
+    spin_lock: SpinLock<R>,
     locked: Cell<bool>,
+    poisoned: Cell<bool>,
     data: UnsafeCell<T>,
 }
 
-impl<T> CMutex<T> {
+impl<T, R: RelaxStrategy> CMutex<T, R> {
     #[inline]
     pub fn new(val: T) -> impl PinInit<Self> {
         pin_init!(Self {
             wait_list <- ListHead::new(),
-            spin_lock: SpinLock::new(),
+            spin_lock: SpinLock::new_with(),
             locked: Cell::new(false),
+            poisoned: Cell::new(false),
             data: UnsafeCell::new(val),
         })
     }
 
     #[inline]
-    pub fn lock(&self) -> CMutexGuard<'_, T> {
-        let mut sguard = self.spin_lock.acquire();
+    pub fn lock(&self) -> LockResult<CMutexGuard<'_, T, R>> {
+        let sguard = self.spin_lock.acquire();
+        if !self.locked.get() {
+            self.locked.set(true);
+            let poisoned = self.poisoned.get();
+            drop(sguard);
+            return Self::guard_result(CMutexGuard { mtx: self }, poisoned);
+        }
+        stack_pin_init!(let wait_entry = WaitEntry::insert_new(&self.wait_list));
+        let wait_entry = match wait_entry {
+            Ok(w) => w,
+            Err(e) => match e {},
+        };
+        // println!("wait list length: {}", self.wait_list.size());
+        drop(sguard);
+        // Spin on our own entry's wake flag rather than `locked`: the
+        // unlocker hands the mutex directly to us, so a fresh `lock()` call
+        // on another thread can never steal it out from under us, and a
+        // spurious `park()` return can never be mistaken for a wakeup.
+        while !wait_entry.wake.load(Ordering::Acquire) {
+            park();
+        }
+        // Re-acquire the spin lock only to safely unlink our now-dead wait
+        // entry from the list; the mutex itself is already ours.
+        let sguard = self.spin_lock.acquire();
+        wait_entry.wait_list.unlink();
+        let poisoned = self.poisoned.get();
+        drop(sguard);
+        Self::guard_result(CMutexGuard { mtx: self }, poisoned)
+    }
+
+    /// Acquires the mutex if it is uncontended, without enqueueing onto the
+    /// wait list. Returns `None` if it was already locked.
+    #[inline]
+    pub fn try_lock(&self) -> Option<LockResult<CMutexGuard<'_, T, R>>> {
+        let sguard = self.spin_lock.acquire();
         if self.locked.get() {
-            stack_pin_init!(let wait_entry = WaitEntry::insert_new(&self.wait_list));
-            let wait_entry = match wait_entry {
-                Ok(w) => w,
-                Err(e) => match e {},
-            };
-            // println!("wait list length: {}", self.wait_list.size());
-            while self.locked.get() {
+            return None;
+        }
+        self.locked.set(true);
+        let poisoned = self.poisoned.get();
+        drop(sguard);
+        Some(Self::guard_result(CMutexGuard { mtx: self }, poisoned))
+    }
+
+    /// Like [`Self::lock`], but gives up and returns `None` if the mutex is
+    /// not acquired within `dur`. Mirrors the finite-timeout variant of the
+    /// SGX usercalls' wait, as opposed to their `WAIT_INDEFINITE` sentinel.
+    pub fn lock_timeout(&self, dur: Duration) -> Option<LockResult<CMutexGuard<'_, T, R>>> {
+        let deadline = Instant::now() + dur;
+        let sguard = self.spin_lock.acquire();
+        if !self.locked.get() {
+            self.locked.set(true);
+            let poisoned = self.poisoned.get();
+            drop(sguard);
+            return Some(Self::guard_result(CMutexGuard { mtx: self }, poisoned));
+        }
+        stack_pin_init!(let wait_entry = WaitEntry::insert_new(&self.wait_list));
+        let wait_entry = match wait_entry {
+            Ok(w) => w,
+            Err(e) => match e {},
+        };
+        drop(sguard);
+        loop {
+            if wait_entry.wake.load(Ordering::Acquire) {
+                let sguard = self.spin_lock.acquire();
+                wait_entry.wait_list.unlink();
+                let poisoned = self.poisoned.get();
                 drop(sguard);
-                park();
-                sguard = self.spin_lock.acquire();
+                return Some(Self::guard_result(CMutexGuard { mtx: self }, poisoned));
             }
-            drop(wait_entry);
+            let Some(remaining) = deadline.checked_duration_since(Instant::now()) else {
+                // Recompute on every spurious wakeup; if time is now up,
+                // splice ourselves out of the wait list before giving up.
+                let sguard = self.spin_lock.acquire();
+                if wait_entry.wake.load(Ordering::Acquire) {
+                    wait_entry.wait_list.unlink();
+                    let poisoned = self.poisoned.get();
+                    drop(sguard);
+                    return Some(Self::guard_result(CMutexGuard { mtx: self }, poisoned));
+                }
+                wait_entry.wait_list.unlink();
+                drop(sguard);
+                return None;
+            };
+            thread::park_timeout(remaining);
+        }
+    }
+
+    #[inline]
+    fn guard_result(
+        guard: CMutexGuard<'_, T, R>,
+        poisoned: bool,
+    ) -> LockResult<CMutexGuard<'_, T, R>> {
+        if poisoned {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
         }
-        self.locked.set(true);
-        CMutexGuard { mtx: self }
     }
 }
 
-unsafe impl<T: Send> Send for CMutex<T> {}
-unsafe impl<T: Send> Sync for CMutex<T> {}
+unsafe impl<T: Send, R> Send for CMutex<T, R> {}
+unsafe impl<T: Send, R> Sync for CMutex<T, R> {}
 
-pub struct CMutexGuard<'a, T> {
-    mtx: &'a CMutex<T>,
+pub struct CMutexGuard<'a, T, R = Spin> {
+    mtx: &'a CMutex<T, R>,
 }
 
-impl<'a, T> Drop for CMutexGuard<'a, T> {
+impl<'a, T, R: RelaxStrategy> Drop for CMutexGuard<'a, T, R> {
     #[inline]
     fn drop(&mut self) {
+        let panicking = thread::panicking();
         let sguard = self.mtx.spin_lock.acquire();
-        self.mtx.locked.set(false);
+        if panicking {
+            self.mtx.poisoned.set(true);
+        }
+        // Direct handoff: if someone is queued, they become the new owner
+        // right now (`locked` stays `true`) and we just wake them; only an
+        // empty queue actually releases the mutex.
         if let Some(list_field) = self.mtx.wait_list.next() {
             let wait_entry = list_field.as_ptr().cast::<WaitEntry>();
-            unsafe { (*wait_entry).thread.unpark() };
+            unsafe {
+                (*wait_entry).wake.store(true, Ordering::Release);
+                (*wait_entry).thread.unpark();
+            }
+        } else {
+            self.mtx.locked.set(false);
         }
         drop(sguard);
     }
 }
 
-impl<'a, T> Deref for CMutexGuard<'a, T> {
+impl<'a, T, R> Deref for CMutexGuard<'a, T, R> {
     type Target = T;
 
     #[inline]
@@ -120,7 +332,7 @@ impl<'a, T> Deref for CMutexGuard<'a, T> {
     }
 }
 
-impl<'a, T> DerefMut for CMutexGuard<'a, T> {
+impl<'a, T, R> DerefMut for CMutexGuard<'a, T, R> {
     #[inline]
     fn deref_mut(&mut self) -> &mut Self::Target {
         unsafe { &mut *self.mtx.data.get() }
@@ -133,6 +345,9 @@ struct WaitEntry {
     #[pin]
     wait_list: ListHead,
     thread: Thread,
+    notified: AtomicBool,
+    wake: AtomicBool,
+    wants_write: Cell<bool>,
 }
 
 impl WaitEntry {
@@ -140,11 +355,284 @@ impl WaitEntry {
     fn insert_new(list: &ListHead) -> impl PinInit<Self> + '_ {
         pin_init!(Self {
             thread: thread::current(),
+            notified: AtomicBool::new(false),
+            wake: AtomicBool::new(false),
+            wants_write: Cell::new(false),
+            wait_list <- ListHead::insert_prev(list),
+        })
+    }
+
+    /// Like [`Self::insert_new`], but tags the entry as wanting exclusive
+    /// (writer) access for [`CRwLock`]'s benefit.
+    #[inline]
+    fn insert_writer(list: &ListHead) -> impl PinInit<Self> + '_ {
+        pin_init!(Self {
+            thread: thread::current(),
+            notified: AtomicBool::new(false),
+            wake: AtomicBool::new(false),
+            wants_write: Cell::new(true),
             wait_list <- ListHead::insert_prev(list),
         })
     }
 }
 
+#[pin_data]
+pub struct CCondvar {
+    #[pin]
+    wait_list: ListHead,
+    spin_lock: SpinLock,
+}
+
+impl CCondvar {
+    #[inline]
+    pub fn new() -> impl PinInit<Self> {
+        pin_init!(Self {
+            wait_list <- ListHead::new(),
+            spin_lock: SpinLock::new(),
+        })
+    }
+
+    /// Atomically unlocks `guard`'s mutex and blocks the current thread,
+    /// re-locking it before returning. Like `std`'s condvars, this may wake
+    /// up spuriously, so callers should still loop on their own predicate.
+    pub fn wait<'a, T, R: RelaxStrategy>(
+        &self,
+        guard: CMutexGuard<'a, T, R>,
+    ) -> LockResult<CMutexGuard<'a, T, R>> {
+        let mtx = guard.mtx;
+        let sguard = self.spin_lock.acquire();
+        stack_pin_init!(let wait_entry = WaitEntry::insert_new(&self.wait_list));
+        let wait_entry = match wait_entry {
+            Ok(w) => w,
+            Err(e) => match e {},
+        };
+        drop(sguard);
+        drop(guard);
+        while !wait_entry.notified.load(Ordering::Acquire) {
+            park();
+        }
+        // Re-acquire the spin lock before unlinking, since `notify_one`/
+        // `notify_all` walk this same wait list under it.
+        let sguard = self.spin_lock.acquire();
+        wait_entry.wait_list.unlink();
+        drop(sguard);
+        mtx.lock()
+    }
+
+    #[inline]
+    pub fn notify_one(&self) {
+        let sguard = self.spin_lock.acquire();
+        if let Some(entry) = self.wait_list.next() {
+            let entry = entry.as_ptr().cast::<WaitEntry>();
+            unsafe {
+                (*entry).notified.store(true, Ordering::Release);
+                (*entry).thread.unpark();
+            }
+        }
+        drop(sguard);
+    }
+
+    #[inline]
+    pub fn notify_all(&self) {
+        let sguard = self.spin_lock.acquire();
+        let head: *const ListHead = &self.wait_list;
+        let mut cur = self.wait_list.next();
+        while let Some(list_field) = cur {
+            // `next()` only reports "nothing after me" for a self-linked
+            // node, so it never returns `None` partway through a real
+            // list; we must instead notice when we've wrapped back around
+            // to the head ourselves before treating it as a `WaitEntry`.
+            if ptr::eq(list_field.as_ptr(), head) {
+                break;
+            }
+            let entry = list_field.as_ptr().cast::<WaitEntry>();
+            cur = unsafe { (*list_field.as_ptr()).next() };
+            unsafe {
+                (*entry).notified.store(true, Ordering::Release);
+                (*entry).thread.unpark();
+            }
+        }
+        drop(sguard);
+    }
+}
+
+unsafe impl Send for CCondvar {}
+unsafe impl Sync for CCondvar {}
+
+/// A reader/writer lock built on the same `ListHead`/`WaitEntry` wait queue
+/// as [`CMutex`]. Queued writers are woken ahead of any reader queued after
+/// them (writer-preferring), so writers cannot starve under read pressure.
+#[pin_data]
+pub struct CRwLock<T> {
+    #[pin]
+    wait_list: ListHead,
+    spin_lock: SpinLock,
+    readers: Cell<usize>,
+    writer: Cell<bool>,
+    data: UnsafeCell<T>,
+}
+
+impl<T> CRwLock<T> {
+    #[inline]
+    pub fn new(val: T) -> impl PinInit<Self> {
+        pin_init!(Self {
+            wait_list <- ListHead::new(),
+            spin_lock: SpinLock::new(),
+            readers: Cell::new(0),
+            writer: Cell::new(false),
+            data: UnsafeCell::new(val),
+        })
+    }
+
+    #[inline]
+    pub fn read(&self) -> CRwLockReadGuard<'_, T> {
+        let sguard = self.spin_lock.acquire();
+        // Block if a writer holds the lock, or if anyone is already queued
+        // ahead of us (which, by construction, can only be a writer or
+        // readers that are themselves waiting behind one).
+        if !self.writer.get() && self.wait_list.is_empty() {
+            self.readers.set(self.readers.get() + 1);
+            drop(sguard);
+            return CRwLockReadGuard { lock: self };
+        }
+        stack_pin_init!(let wait_entry = WaitEntry::insert_new(&self.wait_list));
+        let wait_entry = match wait_entry {
+            Ok(w) => w,
+            Err(e) => match e {},
+        };
+        drop(sguard);
+        while !wait_entry.wake.load(Ordering::Acquire) {
+            park();
+        }
+        let sguard = self.spin_lock.acquire();
+        drop(wait_entry);
+        drop(sguard);
+        CRwLockReadGuard { lock: self }
+    }
+
+    #[inline]
+    pub fn write(&self) -> CRwLockWriteGuard<'_, T> {
+        let sguard = self.spin_lock.acquire();
+        if !self.writer.get() && self.readers.get() == 0 && self.wait_list.is_empty() {
+            self.writer.set(true);
+            drop(sguard);
+            return CRwLockWriteGuard { lock: self };
+        }
+        stack_pin_init!(let wait_entry = WaitEntry::insert_writer(&self.wait_list));
+        let wait_entry = match wait_entry {
+            Ok(w) => w,
+            Err(e) => match e {},
+        };
+        drop(sguard);
+        while !wait_entry.wake.load(Ordering::Acquire) {
+            park();
+        }
+        let sguard = self.spin_lock.acquire();
+        drop(wait_entry);
+        drop(sguard);
+        CRwLockWriteGuard { lock: self }
+    }
+
+    /// Wakes either the next queued writer, or the longest contiguous run
+    /// of queued readers starting at the head, handing each woken waiter
+    /// its share of ownership before unparking it. Must be called while
+    /// holding `spin_lock`.
+    fn wake_next(&self) {
+        let Some(head) = self.wait_list.next() else {
+            return;
+        };
+        let head_entry = head.as_ptr().cast::<WaitEntry>();
+        if unsafe { (*head_entry).wants_write.get() } {
+            self.writer.set(true);
+            unsafe {
+                (*head_entry).wake.store(true, Ordering::Release);
+                (*head_entry).thread.unpark();
+            }
+            return;
+        }
+        let list_head: *const ListHead = &self.wait_list;
+        let mut cur = Some(head);
+        while let Some(list_field) = cur {
+            // As in `CCondvar::notify_all`, `next()` only signals emptiness
+            // for a self-linked node, so we must check for wrapping back
+            // to the list head ourselves before touching it as a
+            // `WaitEntry`.
+            if ptr::eq(list_field.as_ptr(), list_head) {
+                break;
+            }
+            let entry = list_field.as_ptr().cast::<WaitEntry>();
+            if unsafe { (*entry).wants_write.get() } {
+                break;
+            }
+            cur = unsafe { (*list_field.as_ptr()).next() };
+            self.readers.set(self.readers.get() + 1);
+            unsafe {
+                (*entry).wake.store(true, Ordering::Release);
+                (*entry).thread.unpark();
+            }
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for CRwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for CRwLock<T> {}
+
+pub struct CRwLockReadGuard<'a, T> {
+    lock: &'a CRwLock<T>,
+}
+
+impl<'a, T> Drop for CRwLockReadGuard<'a, T> {
+    #[inline]
+    fn drop(&mut self) {
+        let sguard = self.lock.spin_lock.acquire();
+        let readers = self.lock.readers.get() - 1;
+        self.lock.readers.set(readers);
+        if readers == 0 {
+            self.lock.wake_next();
+        }
+        drop(sguard);
+    }
+}
+
+impl<'a, T> Deref for CRwLockReadGuard<'a, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+pub struct CRwLockWriteGuard<'a, T> {
+    lock: &'a CRwLock<T>,
+}
+
+impl<'a, T> Drop for CRwLockWriteGuard<'a, T> {
+    #[inline]
+    fn drop(&mut self) {
+        let sguard = self.lock.spin_lock.acquire();
+        self.lock.writer.set(false);
+        self.lock.wake_next();
+        drop(sguard);
+    }
+}
+
+impl<'a, T> Deref for CRwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for CRwLockWriteGuard<'a, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
 fn main() {
     let mtx: Pin<Arc<CMutex<usize>>> = Arc::pin_init(CMutex::new(0)).unwrap();
     let mut handles = vec![];
@@ -157,12 +645,12 @@ fn main() {
                 .name(format!("worker #{i}"))
                 .spawn(move || {
                     for _ in 0..workload {
-                        *mtx.lock() += 1;
+                        *mtx.lock().unwrap() += 1;
                     }
                     println!("{i} halfway");
                     sleep(Duration::from_millis((i as u64) * 10));
                     for _ in 0..workload {
-                        *mtx.lock() += 1;
+                        *mtx.lock().unwrap() += 1;
                     }
                     println!("{i} finished");
                 })
@@ -172,6 +660,6 @@ fn main() {
     for h in handles {
         h.join().expect("thread paniced");
     }
-    println!("{:?}", &*mtx.lock());
-    assert_eq!(*mtx.lock(), workload * thread_count * 2);
+    println!("{:?}", &*mtx.lock().unwrap());
+    assert_eq!(*mtx.lock().unwrap(), workload * thread_count * 2);
 }